@@ -1,33 +1,194 @@
+mod registry;
+#[allow(dead_code)]
 mod scratch;
+mod wait;
+
+pub use registry::{DatabaseBuilder, HasRegistry, Registry};
+pub use wait::{BusySpin, Park, WaitStrategy, Waiter, Wakeup, YieldThenPark};
 
-use crossbeam::sync::{Parker, Unparker};
 use dashmap::DashMap;
-use fxhash::FxBuildHasher;
-use std::{cell::RefCell, collections::VecDeque, hash::Hash, rc::Rc, thread::ThreadId};
+use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    hash::Hash,
+    sync::{
+        Arc, Condvar, Mutex, OnceLock, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::ThreadId,
+    time::{Duration, Instant},
+};
 
 type FxDashMap<K, V> = DashMap<K, V, FxBuildHasher>;
+type Waiters = Arc<Mutex<Vec<(ThreadId, Wakeup)>>>;
+type Handoff<Result, Query> = Arc<OnceLock<(Result, Vec<Query>)>>;
+type Task<DB> = Box<dyn FnOnce(&Context<DB>) + Send>;
+type Meta = Arc<dyn Any + Send + Sync>;
+type OnProgress = Arc<dyn Fn(Progress) + Send + Sync>;
 
-trait Database
+pub trait Database
 where
-    Self: Sized,
+    Self: Sized + Send + Sync,
 {
-    type Query: Clone + Eq + std::fmt::Debug;
+    type Query: Clone + Eq + Hash + std::fmt::Debug + Send + Sync;
 
     fn dispatch<D>(d: D, q: Self::Query) -> D::Result
     where
         D: Dispatch<Self>;
 }
 
-struct Context<DB: Database> {
-    query_dependencies: RefCell<Vec<DB::Query>>,
-    stealable: RefCell<Vec<Stealable<DB::Query>>>,
-    thieves: RefCell<VecDeque<(ThreadId, Unparker)>>,
+pub struct Context<DB: Database> {
+    threads: DashMap<ThreadId, Arc<ThreadState<DB::Query>>>,
+    stealable: Mutex<Pool<DB>>,
+    thieves: Mutex<VecDeque<(ThreadId, Wakeup)>>,
+    database: Arc<DB>,
+    thread_dependencies: Arc<DashMap<ThreadId, (ThreadId, Wakeup)>>,
+    wait_strategy: Arc<dyn WaitStrategy>,
+    deduplicate_dependencies: bool,
+    computations: Arc<Computations>,
+    steal_counters: StealCounters,
+}
+
+#[derive(Default)]
+struct StealCounters {
+    attempts: AtomicU64,
+    successful: AtomicU64,
+    empty: AtomicU64,
+    wasted: AtomicU64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    pub steal_attempts: u64,
+    pub successful_steals: u64,
+    pub empty_steals: u64,
+    pub wasted_steals: u64,
+}
+
+struct ThreadState<Query> {
+    query_dependencies: Mutex<Vec<Query>>,
+    uncached: AtomicBool,
+    do_not_cache: AtomicBool,
+    untracked: AtomicBool,
+    recompute_count: AtomicU64,
+    rule_depth: AtomicU64,
+    rule_budget: AtomicU64,
+    request_meta: Mutex<Option<Meta>>,
+    progress: Mutex<Option<(usize, OnProgress)>>,
+    // Nanoseconds spent waiting on other threads or on stolen work, which
+    // doesn't count towards the compute time of the rules running meanwhile.
+    excluded_time: AtomicU64,
+}
+
+impl<Query> ThreadState<Query> {
+    fn exclude_time_since(&self, started: Instant) {
+        self.excluded_time
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The calling thread's state in a context. The context drops a thread's
+/// state along with the last handle to it, when the thread's outermost call
+/// into the context returns, so the threads map only holds threads that are
+/// currently using the context.
+struct ThreadHandle<'a, DB: Database> {
+    context: &'a Context<DB>,
+    state: Arc<ThreadState<DB::Query>>,
+}
+
+impl<DB: Database> std::ops::Deref for ThreadHandle<'_, DB> {
+    type Target = ThreadState<DB::Query>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<DB: Database> Drop for ThreadHandle<'_, DB> {
+    fn drop(&mut self) {
+        // Only this thread clones its state, so the map holds the only other
+        // reference once this is the outermost handle.
+        if Arc::strong_count(&self.state) == 2 {
+            self.context.threads.remove(&std::thread::current().id());
+        }
+    }
+}
+
+impl<Query> Default for ThreadState<Query> {
+    fn default() -> Self {
+        ThreadState {
+            query_dependencies: Mutex::new(Vec::new()),
+            uncached: AtomicBool::new(false),
+            do_not_cache: AtomicBool::new(false),
+            untracked: AtomicBool::new(false),
+            recompute_count: AtomicU64::new(0),
+            rule_depth: AtomicU64::new(0),
+            rule_budget: AtomicU64::new(u64::MAX),
+            request_meta: Mutex::new(None),
+            progress: Mutex::new(None),
+            excluded_time: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Computations {
+    active: Mutex<usize>,
+    quiescent: Condvar,
+}
+
+impl Computations {
+    fn enter(&self) {
+        *self.active.lock().unwrap() += 1;
+    }
+
+    fn leave(&self) {
+        let mut active = self.active.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.quiescent.notify_all();
+        }
+    }
+}
+
+/// Keeps a stolen query or task counted as active until it has finished, so
+/// that `quiesce` doesn't return between it leaving the pool and its
+/// computation starting.
+struct Active<'a>(&'a Computations);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.leave();
+    }
+}
+
+pub struct ContextBuilder<DB: Database> {
     database: DB,
-    thread_dependencies: DashMap<ThreadId, ThreadId>,
+    wait_strategy: Arc<dyn WaitStrategy>,
+    deduplicate_dependencies: bool,
+    expected_threads: usize,
+}
+
+enum Stealable<DB: Database> {
+    Query(DB::Query),
+    Task(Task<DB>),
+}
+
+/// Work offered to waiting threads, along with the queries among it so that
+/// offering one twice is cheap to detect.
+struct Pool<DB: Database> {
+    work: Vec<Stealable<DB>>,
+    queries: FxHashSet<DB::Query>,
 }
 
-struct Stealable<Q> {
-    query: Q,
+impl<DB: Database> Default for Pool<DB> {
+    fn default() -> Self {
+        Pool {
+            work: Vec::new(),
+            queries: FxHashSet::default(),
+        }
+    }
 }
 
 struct Theft<'a, DB: Database> {
@@ -35,49 +196,261 @@ struct Theft<'a, DB: Database> {
 }
 
 impl<DB: Database> Dispatch<DB> for Theft<'_, DB> {
-    type Result = ();
+    type Result = bool;
 
     fn dispatch<Q: Query<DB>>(self, query: Q) -> Self::Result {
         let map = Q::storage(&self.context.database);
-        let waiters = match map.entry(query.clone()) {
-            dashmap::Entry::Occupied(_) => return,
+        let (computation, recompute_count, compute_time) = match map.entry(query.clone()) {
+            dashmap::Entry::Occupied(mut occupied_entry) => match occupied_entry.get() {
+                Entry::Complete {
+                    computed_at,
+                    recompute_count,
+                    compute_time,
+                    mocked,
+                    stale,
+                    ..
+                } if !is_fresh::<DB, Q>(computed_at, *mocked, *stale) => {
+                    let (recompute_count, compute_time) = (recompute_count + 1, *compute_time);
+                    let (entry, computation) = self.context.start_computation(query);
+                    occupied_entry.insert(entry);
+                    (computation, recompute_count, compute_time)
+                }
+                _ => return false,
+            },
             dashmap::Entry::Vacant(vacant_entry) => {
-                let waiters = Rc::new(RefCell::new(Vec::new()));
-                let tid = std::thread::current().id();
-                vacant_entry.insert(Entry::InProgress {
-                    thread_id: tid,
-                    waiters: waiters.clone(),
-                });
-                waiters
+                let (entry, computation) = self.context.start_computation(query);
+                vacant_entry.insert(entry);
+                (computation, 0, Duration::ZERO)
             }
         };
-
-        let (result, dependencies) = self.context.rule(&query);
-        map.insert(
-            query,
-            Entry::Complete {
-                result,
-                dependencies,
-            },
+        let thread = self.context.thread_state();
+        self.context.finish_computation(
+            &thread,
+            computation,
+            recompute_count,
+            compute_time,
+            |_, _| (),
         );
-        for (waiting_thread_id, waiter) in waiters.borrow().iter() {
-            self.context.thread_dependencies.remove(waiting_thread_id);
-            waiter.unpark();
+        true
+    }
+}
+
+struct IsStored<'a, DB: Database> {
+    context: &'a Context<DB>,
+}
+
+impl<DB: Database> Dispatch<DB> for IsStored<'_, DB> {
+    type Result = bool;
+
+    fn dispatch<Q: Query<DB>>(self, query: Q) -> Self::Result {
+        Q::storage(&self.context.database)
+            .get(&query)
+            .is_some_and(|entry| match entry.value() {
+                Entry::InProgress { .. } => true,
+                Entry::Complete {
+                    computed_at,
+                    mocked,
+                    stale,
+                    ..
+                } => is_fresh::<DB, Q>(computed_at, *mocked, *stale),
+            })
+    }
+}
+
+struct DependenciesOf<'a, DB: Database> {
+    context: &'a Context<DB>,
+}
+
+impl<DB: Database> Dispatch<DB> for DependenciesOf<'_, DB> {
+    type Result = Option<Vec<DB::Query>>;
+
+    fn dispatch<Q: Query<DB>>(self, query: Q) -> Self::Result {
+        self.context.dependencies_of(&query)
+    }
+}
+
+pub struct WeakContext<DB: Database> {
+    context: Weak<Context<DB>>,
+}
+
+impl<DB: Database> WeakContext<DB> {
+    pub fn upgrade(&self) -> Option<Arc<Context<DB>>> {
+        self.context.upgrade()
+    }
+}
+
+impl<DB: Database> Clone for WeakContext<DB> {
+    fn clone(&self) -> Self {
+        WeakContext {
+            context: self.context.clone(),
         }
     }
 }
 
-trait Query<DB: Database>
+pub struct Scope<'scope, 'env, DB: Database> {
+    context: &'env Context<DB>,
+    scope: &'scope std::thread::Scope<'scope, 'env>,
+    uncached: bool,
+    dependencies: Arc<Mutex<Vec<DB::Query>>>,
+}
+
+impl<'scope, 'env, DB: Database> Scope<'scope, 'env, DB> {
+    pub fn spawn<T: Send + 'scope>(
+        &self,
+        f: impl FnOnce(&Context<DB>) -> T + Send + 'scope,
+    ) -> std::thread::ScopedJoinHandle<'scope, T> {
+        let (context, uncached) = (self.context, self.uncached);
+        let dependencies = self.dependencies.clone();
+        self.scope.spawn(move || {
+            let thread = context.thread_state();
+            let frame = DependencyFrame::enter(&thread.query_dependencies);
+            let uncached = Restore::set(&thread.uncached, uncached);
+            let output = f(context);
+            dependencies
+                .lock()
+                .unwrap()
+                .append(&mut thread.query_dependencies.lock().unwrap());
+            drop(uncached);
+            drop(frame);
+            output
+        })
+    }
+}
+
+pub trait Query<DB: Database>
 where
-    Self: Clone + Eq + Hash + Into<DB::Query>,
+    Self: Clone + Eq + Hash + Into<DB::Query> + Send + Sync,
 {
-    type Result: Clone;
+    type Result: Clone + Send + Sync;
+
+    const TTL: Option<Duration> = None;
 
     fn storage(db: &DB) -> &FxDashMap<Self, Entry<Self::Result, DB::Query>>;
     fn rule(qc: &Context<DB>, query: &Self) -> Self::Result;
+
+    fn on_reentry(_query: &Self) -> Reentry<Self::Result> {
+        Reentry::Error
+    }
+}
+
+/// What a fetch of a query returns when the same thread is already in the
+/// middle of computing it. With `Provisional`, queries computed from the
+/// provisional value are cached as usual.
+pub enum Reentry<Result> {
+    Error,
+    Provisional(Result),
+    Recompute,
+}
+
+/// A rule's panic, caught by `fetch_catch`. The message ends with the chain
+/// of queries that were being computed, innermost first. The chain is only
+/// known once the panic has unwound out of the rules, so the panic hook, which
+/// runs first, prints just the original message.
+#[derive(Debug)]
+pub struct RulePanic {
+    pub message: String,
+}
+
+impl RulePanic {
+    /// Payloads other than panic messages, such as `BudgetExceeded`, are
+    /// resumed rather than caught, so that they reach whoever raised them for.
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<QueryPanic>() {
+            Ok(query_panic) => query_panic.to_string(),
+            Err(payload) => match payload.downcast::<String>() {
+                Ok(message) => *message,
+                Err(payload) => match payload.downcast::<&str>() {
+                    Ok(message) => message.to_string(),
+                    Err(payload) => std::panic::resume_unwind(payload),
+                },
+            },
+        };
+        RulePanic { message }
+    }
+}
+
+struct QueryPanic {
+    payload: Box<dyn Any + Send>,
+    stack: Vec<String>,
+    backtraces: Vec<String>,
 }
 
-trait Dispatch<DB: Database> {
+impl QueryPanic {
+    /// Wraps a panic message so the queries it unwinds through can be added
+    /// to it. Any other payload is resumed unchanged, so that it can still be
+    /// downcast by whoever catches it.
+    fn new(payload: Box<dyn Any + Send>) -> Box<Self> {
+        if payload.is::<String>() || payload.is::<&str>() {
+            return Box::new(QueryPanic {
+                payload,
+                stack: Vec::new(),
+                backtraces: Vec::new(),
+            });
+        }
+        payload
+            .downcast::<QueryPanic>()
+            .unwrap_or_else(|payload| std::panic::resume_unwind(payload))
+    }
+
+    fn message(&self) -> &str {
+        match self.payload.downcast_ref::<String>() {
+            Some(message) => message,
+            None => self.payload.downcast_ref::<&str>().unwrap(),
+        }
+    }
+}
+
+impl std::fmt::Display for QueryPanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\nwhile computing {}",
+            self.message(),
+            self.stack.join(" <- ")
+        )?;
+        for backtrace in &self.backtraces {
+            write!(f, "\n\n{backtrace}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for RulePanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rule panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for RulePanic {}
+
+#[derive(Debug)]
+pub struct BudgetExceeded;
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rule budget exceeded")
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Progress {
+    pub completed: usize,
+}
+
+pub trait Materialize {
+    type Output;
+
+    fn materialize(&self) -> Self::Output;
+}
+
+pub trait Monoid {
+    fn empty() -> Self;
+    fn combine(self, other: Self) -> Self;
+}
+
+pub trait Dispatch<DB: Database> {
     type Result;
     fn dispatch<Q: Query<DB>>(self, query: Q) -> Self::Result;
 }
@@ -86,117 +459,1199 @@ trait Dispatch<DB: Database> {
 pub enum Entry<Result, Query> {
     InProgress {
         thread_id: ThreadId,
-        waiters: Rc<RefCell<Vec<(ThreadId, Unparker)>>>,
+        waiters: Arc<Mutex<Vec<(ThreadId, Wakeup)>>>,
+        handoff: Arc<OnceLock<(Result, Vec<Query>)>>,
+        invalidated: Arc<AtomicBool>,
     },
     Complete {
         result: Result,
         dependencies: Vec<Query>,
+        computed_at: Instant,
+        recompute_count: u64,
+        compute_time: Duration,
+        mocked: bool,
+        /// Set by `invalidate_type`. A stale entry is recomputed on its next
+        /// fetch as if it had expired.
+        stale: bool,
     },
 }
 
-enum TryFetch<Result, Query> {
-    Stole(Stealable<Query>),
-    WaitFor(Parker),
-    Complete(Result),
+struct DependencyFrame<'a, Query> {
+    query_dependencies: &'a Mutex<Vec<Query>>,
+    saved_dependencies: Vec<Query>,
+}
+
+impl<'a, Query> DependencyFrame<'a, Query> {
+    fn enter(query_dependencies: &'a Mutex<Vec<Query>>) -> Self {
+        DependencyFrame {
+            query_dependencies,
+            saved_dependencies: std::mem::take(&mut *query_dependencies.lock().unwrap()),
+        }
+    }
+}
+
+impl<Query> Drop for DependencyFrame<'_, Query> {
+    fn drop(&mut self) {
+        *self.query_dependencies.lock().unwrap() = std::mem::take(&mut self.saved_dependencies);
+    }
+}
+
+trait Swap {
+    type Value;
+    fn swap(&self, value: Self::Value) -> Self::Value;
+}
+
+impl Swap for AtomicBool {
+    type Value = bool;
+
+    fn swap(&self, value: bool) -> bool {
+        self.swap(value, Ordering::Relaxed)
+    }
+}
+
+impl Swap for AtomicU64 {
+    type Value = u64;
+
+    fn swap(&self, value: u64) -> u64 {
+        self.swap(value, Ordering::Relaxed)
+    }
+}
+
+impl<T> Swap for Mutex<T> {
+    type Value = T;
+
+    fn swap(&self, value: T) -> T {
+        std::mem::replace(&mut *self.lock().unwrap(), value)
+    }
+}
+
+struct Restore<'a, S: Swap> {
+    cell: &'a S,
+    saved: Option<S::Value>,
+}
+
+impl<'a, S: Swap> Restore<'a, S> {
+    fn set(cell: &'a S, value: S::Value) -> Self {
+        Restore {
+            cell,
+            saved: Some(Swap::swap(cell, value)),
+        }
+    }
+}
+
+impl<S: Swap> Drop for Restore<'_, S> {
+    fn drop(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            Swap::swap(self.cell, saved);
+        }
+    }
+}
+
+struct Computation<'a, DB: Database, Q: Query<DB>> {
+    context: &'a Context<DB>,
+    query: Option<Q>,
+    waiters: Waiters,
+    handoff: Handoff<Q::Result, DB::Query>,
+    invalidated: Arc<AtomicBool>,
+}
+
+impl<DB: Database, Q: Query<DB>> Computation<'_, DB, Q> {
+    /// Whether `entry` is still the in-progress entry this computation
+    /// inserted, rather than one that replaced it, e.g. a mock.
+    fn owns(&self, entry: &Entry<Q::Result, DB::Query>) -> bool {
+        matches!(entry, Entry::InProgress { waiters, .. } if Arc::ptr_eq(waiters, &self.waiters))
+    }
+}
+
+impl<DB: Database, Q: Query<DB>> Drop for Computation<'_, DB, Q> {
+    fn drop(&mut self) {
+        if let Some(query) = self.query.take() {
+            Q::storage(&self.context.database).remove_if(&query, |_, entry| self.owns(entry));
+        }
+        for (_, waiter) in self.waiters.lock().unwrap().iter() {
+            waiter.wake();
+        }
+        self.context.computations.leave();
+    }
+}
+
+/// A thread waiting on another thread's computation, offering itself as a
+/// thief. Dropping it, including on unwind, removes the thread's wait-for edge
+/// before it retries or steals.
+struct Waiting<'a, DB: Database> {
+    context: &'a Context<DB>,
+}
+
+impl<'a, DB: Database> Waiting<'a, DB> {
+    fn enter(context: &'a Context<DB>, waiter: &Waiter) -> Self {
+        context
+            .thieves
+            .lock()
+            .unwrap()
+            .push_back((std::thread::current().id(), waiter.wakeup()));
+        Waiting { context }
+    }
+}
+
+impl<DB: Database> Drop for Waiting<'_, DB> {
+    fn drop(&mut self) {
+        let my_tid = std::thread::current().id();
+        self.context.thread_dependencies.remove(&my_tid);
+        self.context
+            .thieves
+            .lock()
+            .unwrap()
+            .retain(|(tid, _)| *tid != my_tid);
+    }
+}
+
+struct Computed<Result, Query> {
+    result: Result,
+    dependencies: Vec<Query>,
+    cache: bool,
+}
+
+enum TryFetch<T, Result, DB: Database> {
+    Stole(Stealable<DB>),
+    WaitFor(Waiter, Handoff<Result, DB::Query>),
+    Complete(T, FetchOutcome),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FetchOutcome {
+    CacheHit,
+    Recomputed,
+    FreshComputed,
+    Provisional,
+    /// Computed by another thread while this one waited, and handed over
+    /// without being cached.
+    HandedOff,
+}
+
+fn is_fresh<DB: Database, Q: Query<DB>>(computed_at: &Instant, mocked: bool, stale: bool) -> bool {
+    mocked || !stale && Q::TTL.is_none_or(|ttl| computed_at.elapsed() < ttl)
+}
+
+impl<DB: Database> ContextBuilder<DB> {
+    pub fn new(database: DB) -> Self {
+        ContextBuilder {
+            database,
+            wait_strategy: Arc::new(Park),
+            deduplicate_dependencies: true,
+            expected_threads: 0,
+        }
+    }
+
+    pub fn wait_strategy(mut self, wait_strategy: impl WaitStrategy + 'static) -> Self {
+        self.wait_strategy = Arc::new(wait_strategy);
+        self
+    }
+
+    pub fn deduplicate_dependencies(mut self, deduplicate_dependencies: bool) -> Self {
+        self.deduplicate_dependencies = deduplicate_dependencies;
+        self
+    }
+
+    pub fn expected_threads(mut self, expected_threads: usize) -> Self {
+        self.expected_threads = expected_threads;
+        self
+    }
+
+    pub fn build(self) -> Context<DB> {
+        Context {
+            threads: DashMap::with_capacity(self.expected_threads),
+            stealable: Mutex::default(),
+            thieves: Mutex::new(VecDeque::with_capacity(self.expected_threads)),
+            database: Arc::new(self.database),
+            thread_dependencies: Arc::new(DashMap::with_capacity(self.expected_threads)),
+            wait_strategy: self.wait_strategy,
+            deduplicate_dependencies: self.deduplicate_dependencies,
+            computations: Arc::default(),
+            steal_counters: StealCounters::default(),
+        }
+    }
 }
 
 impl<DB: Database> Context<DB> {
-    fn deadlock_check(&self, other_tid: ThreadId) {
+    pub fn new(database: DB) -> Self {
+        ContextBuilder::new(database).build()
+    }
+
+    pub fn database(&self) -> &DB {
+        &self.database
+    }
+
+    fn thread_state(&self) -> ThreadHandle<'_, DB> {
+        let thread_id = std::thread::current().id();
+        let state = match self.threads.get(&thread_id) {
+            Some(state) => state.clone(),
+            None => self.threads.entry(thread_id).or_default().clone(),
+        };
+        ThreadHandle {
+            context: self,
+            state,
+        }
+    }
+
+    /// Records that this thread waits on `other_tid` until `wakeup` is woken.
+    /// Edges of threads that have been woken are ignored, since those threads
+    /// are about to retry or steal rather than wait.
+    fn deadlock_check(&self, other_tid: ThreadId, wakeup: Wakeup) {
         let my_tid = std::thread::current().id();
-        self.thread_dependencies.insert(my_tid, other_tid);
+        self.thread_dependencies.insert(my_tid, (other_tid, wakeup));
         let mut current = other_tid;
-        while let Some(next) = self.thread_dependencies.get(&current).map(|entry| *entry) {
+        for _ in 0..self.thread_dependencies.len() {
+            let Some(next) = self
+                .thread_dependencies
+                .get(&current)
+                .filter(|entry| !entry.1.is_woken())
+                .map(|entry| entry.0)
+            else {
+                return;
+            };
             if next == my_tid {
+                self.thread_dependencies.remove(&my_tid);
                 panic!("cyclic query detected");
             }
             current = next;
         }
     }
 
-    fn rule<Q: Query<DB>>(&self, query: &Q) -> (Q::Result, Vec<DB::Query>) {
-        let saved_dependencies = self.query_dependencies.take();
-        let result = Q::rule(self, query);
-        let query_dependencies = self.query_dependencies.replace(saved_dependencies);
-        (result, query_dependencies)
+    fn rule<Q: Query<DB>>(
+        &self,
+        thread: &ThreadState<DB::Query>,
+        query: &Q,
+        recompute_count: u64,
+    ) -> Computed<Q::Result, DB::Query> {
+        let budget = thread.rule_budget.load(Ordering::Relaxed);
+        if budget != u64::MAX {
+            if budget == 0 {
+                std::panic::resume_unwind(Box::new(BudgetExceeded));
+            }
+            thread.rule_budget.store(budget - 1, Ordering::Relaxed);
+        }
+        let frame = DependencyFrame::enter(&thread.query_dependencies);
+        let do_not_cache = Restore::set(&thread.do_not_cache, false);
+        let untracked = Restore::set(&thread.untracked, false);
+        let recompute_count = Restore::set(&thread.recompute_count, recompute_count);
+        let depth = thread.rule_depth.load(Ordering::Relaxed);
+        let rule_depth = Restore::set(&thread.rule_depth, depth + 1);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Q::rule(self, query)))
+                .unwrap_or_else(|payload| {
+                    let mut query_panic = QueryPanic::new(payload);
+                    query_panic
+                        .stack
+                        .push(format!("{:?}", query.clone().into()));
+                    if depth == 0 {
+                        std::panic::resume_unwind(Box::new(query_panic.to_string()));
+                    }
+                    std::panic::resume_unwind(query_panic)
+                });
+        let computed = Computed {
+            result,
+            dependencies: std::mem::take(&mut *thread.query_dependencies.lock().unwrap()),
+            cache: !thread.do_not_cache.load(Ordering::Relaxed),
+        };
+        drop(rule_depth);
+        drop(recompute_count);
+        drop(untracked);
+        drop(do_not_cache);
+        drop(frame);
+        computed
+    }
+
+    /// Takes the most recently offered work from the pool, counting it as
+    /// active until the `Active` guard made for it in `steal` is dropped.
+    fn pop_stealable(&self) -> Option<Stealable<DB>> {
+        let mut pool = self.stealable.lock().unwrap();
+        let popped = pool.work.pop()?;
+        if let Stealable::Query(query) = &popped {
+            pool.queries.remove(query);
+        }
+        self.computations.enter();
+        Some(popped)
     }
 
-    fn steal(&self, stealable: Stealable<DB::Query>) {
-        DB::dispatch(Theft { context: self }, stealable.query);
+    /// Runs stolen work in isolation from whatever this thread was doing, so
+    /// that it neither uses up the thread's budget, nor counts towards its
+    /// progress, nor nests under its rule in panic messages, nor unwinds it. A stolen
+    /// query that panics is left to panic again on the thread that fetches it,
+    /// which its computation's cleanup has already woken.
+    fn steal(&self, stealable: Stealable<DB>) {
+        let thread = self.thread_state();
+        let _budget = Restore::set(&thread.rule_budget, u64::MAX);
+        let _depth = Restore::set(&thread.rule_depth, 0);
+        let _progress = Restore::set(&thread.progress, None);
+        let mut next = Some(stealable);
+        while let Some(stealable) = next {
+            let _active = Active(&self.computations);
+            match stealable {
+                Stealable::Query(query) => {
+                    let stolen = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        DB::dispatch(Theft { context: self }, query)
+                    }));
+                    if stolen.unwrap_or(true) {
+                        self.steal_counters
+                            .successful
+                            .fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    self.steal_counters.wasted.fetch_add(1, Ordering::Relaxed);
+                }
+                Stealable::Task(task) => {
+                    self.steal_counters
+                        .successful
+                        .fetch_add(1, Ordering::Relaxed);
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        self.run_task(task)
+                    }));
+                    return;
+                }
+            }
+            next = self.pop_stealable();
+        }
     }
 
-    fn try_fetch<Q: Query<DB>>(&self, query: Q) -> TryFetch<Q::Result, DB::Query> {
+    fn run_task(&self, task: Task<DB>) {
+        let thread = self.thread_state();
+        let _frame = DependencyFrame::enter(&thread.query_dependencies);
+        let _uncached = Restore::set(&thread.uncached, false);
+        let _do_not_cache = Restore::set(&thread.do_not_cache, false);
+        task(self)
+    }
+
+    /// Offers work to waiting threads. Queries already in progress or stored,
+    /// or already offered, are skipped, so that hints a rule ends up
+    /// computing itself don't pile up in the pool.
+    fn push_stealable(&self, stealable: Stealable<DB>) {
+        if let Stealable::Query(query) = &stealable
+            && DB::dispatch(IsStored { context: self }, query.clone())
+        {
+            return;
+        }
+        {
+            let mut pool = self.stealable.lock().unwrap();
+            if let Stealable::Query(query) = &stealable
+                && !pool.queries.insert(query.clone())
+            {
+                return;
+            }
+            pool.work.push(stealable);
+        }
+        if let Some((_, wakeup)) = self.thieves.lock().unwrap().pop_front() {
+            wakeup.wake();
+        }
+    }
+
+    fn try_fetch<Q: Query<DB>, T>(
+        &self,
+        thread: &ThreadState<DB::Query>,
+        query: Q,
+        read: &impl Fn(&Q::Result, &[DB::Query]) -> T,
+    ) -> TryFetch<T, Q::Result, DB> {
         let map = Q::storage(&self.database);
-        let waiters = match map.entry(query.clone()) {
+        if let Some(entry) = map.get(&query)
+            && let Entry::Complete {
+                result,
+                dependencies,
+                computed_at,
+                mocked,
+                stale,
+                ..
+            } = entry.value()
+            && is_fresh::<DB, Q>(computed_at, *mocked, *stale)
+        {
+            return TryFetch::Complete(read(result, dependencies), FetchOutcome::CacheHit);
+        }
+        if self.owner_of(&query) == Some(std::thread::current().id()) {
+            match Q::on_reentry(&query) {
+                Reentry::Error => {}
+                Reentry::Provisional(result) => {
+                    return TryFetch::Complete(read(&result, &[]), FetchOutcome::Provisional);
+                }
+                Reentry::Recompute => {
+                    let computed = self.rule(thread, &query, 0);
+                    return TryFetch::Complete(
+                        read(&computed.result, &computed.dependencies),
+                        FetchOutcome::FreshComputed,
+                    );
+                }
+            }
+        }
+        let (computation, recompute_count, compute_time, outcome) = match map.entry(query.clone()) {
             dashmap::Entry::Occupied(mut occupied_entry) => match occupied_entry.get() {
                 Entry::InProgress { .. } => {
-                    let Entry::InProgress { thread_id, waiters } = occupied_entry.get_mut() else {
+                    let Entry::InProgress {
+                        thread_id,
+                        waiters,
+                        handoff,
+                        ..
+                    } = occupied_entry.get_mut()
+                    else {
                         unreachable!()
                     };
-                    if let Some(stealable) = self.stealable.borrow_mut().pop() {
+                    self.steal_counters.attempts.fetch_add(1, Ordering::Relaxed);
+                    if let Some(stealable) = self.pop_stealable() {
                         return TryFetch::Stole(stealable);
                     }
-                    let parker = Parker::new();
-                    let unparker = parker.unparker();
-                    waiters
-                        .borrow_mut()
-                        .push((std::thread::current().id(), unparker.clone()));
-                    self.deadlock_check(*thread_id);
-                    return TryFetch::WaitFor(parker);
+                    self.steal_counters.empty.fetch_add(1, Ordering::Relaxed);
+                    // A thread that was woken and came back to wait again
+                    // replaces its earlier wakeup.
+                    let waiter = Waiter::new();
+                    let my_tid = std::thread::current().id();
+                    let mut waiters = waiters.lock().unwrap();
+                    waiters.retain(|(waiting, _)| *waiting != my_tid);
+                    waiters.push((my_tid, waiter.wakeup()));
+                    drop(waiters);
+                    self.deadlock_check(*thread_id, waiter.wakeup());
+                    return TryFetch::WaitFor(waiter, handoff.clone());
                 }
-                Entry::Complete { result, .. } => {
-                    return TryFetch::Complete(result.clone());
+                Entry::Complete {
+                    result,
+                    dependencies,
+                    computed_at,
+                    recompute_count,
+                    compute_time,
+                    mocked,
+                    stale,
+                } => {
+                    if is_fresh::<DB, Q>(computed_at, *mocked, *stale) {
+                        return TryFetch::Complete(
+                            read(result, dependencies),
+                            FetchOutcome::CacheHit,
+                        );
+                    }
+                    let (recompute_count, compute_time) = (recompute_count + 1, *compute_time);
+                    let (entry, computation) = self.start_computation(query);
+                    occupied_entry.insert(entry);
+                    (
+                        computation,
+                        recompute_count,
+                        compute_time,
+                        FetchOutcome::Recomputed,
+                    )
                 }
             },
             dashmap::Entry::Vacant(vacant_entry) => {
-                let waiters = Rc::new(RefCell::new(Vec::new()));
-                let tid = std::thread::current().id();
-                vacant_entry.insert(Entry::InProgress {
-                    thread_id: tid,
-                    waiters: waiters.clone(),
-                });
-                waiters
+                let (entry, computation) = self.start_computation(query);
+                vacant_entry.insert(entry);
+                (computation, 0, Duration::ZERO, FetchOutcome::FreshComputed)
             }
         };
+        TryFetch::Complete(
+            self.finish_computation(thread, computation, recompute_count, compute_time, read),
+            outcome,
+        )
+    }
 
-        let (result, dependencies) = self.rule(&query);
-        map.insert(
-            query,
+    fn start_computation<Q: Query<DB>>(
+        &self,
+        query: Q,
+    ) -> (Entry<Q::Result, DB::Query>, Computation<'_, DB, Q>) {
+        self.computations.enter();
+        let waiters = Waiters::default();
+        let handoff = Handoff::default();
+        let invalidated = Arc::new(AtomicBool::new(false));
+        let entry = Entry::InProgress {
+            thread_id: std::thread::current().id(),
+            waiters: waiters.clone(),
+            handoff: handoff.clone(),
+            invalidated: invalidated.clone(),
+        };
+        let computation = Computation {
+            context: self,
+            query: Some(query),
+            waiters,
+            handoff,
+            invalidated,
+        };
+        (entry, computation)
+    }
+
+    fn finish_computation<Q: Query<DB>, T>(
+        &self,
+        thread: &ThreadState<DB::Query>,
+        mut computation: Computation<'_, DB, Q>,
+        recompute_count: u64,
+        compute_time: Duration,
+        read: impl FnOnce(&Q::Result, &[DB::Query]) -> T,
+    ) -> T {
+        let started = Instant::now();
+        let excluded_time = thread.excluded_time.load(Ordering::Relaxed);
+        let Computed {
+            result,
+            dependencies,
+            cache,
+        } = self.rule(thread, computation.query.as_ref().unwrap(), recompute_count);
+        let output = read(&result, &dependencies);
+        // Checked under the entry's lock, which `invalidate_type` also holds
+        // while flagging it, so a result from before an invalidation can't
+        // slip into the cache.
+        let map = Q::storage(&self.database);
+        let entry = map
+            .get_mut(computation.query.as_ref().unwrap())
+            .filter(|entry| computation.owns(entry))
+            .filter(|_| !computation.invalidated.load(Ordering::Relaxed));
+        if let Some(mut entry) = entry
+            && cache
+        {
+            let computed_at = Instant::now();
+            let excluded_time =
+                Duration::from_nanos(thread.excluded_time.load(Ordering::Relaxed) - excluded_time);
+            *entry = Entry::Complete {
+                result,
+                dependencies,
+                computed_at,
+                recompute_count,
+                compute_time: compute_time + (computed_at - started).saturating_sub(excluded_time),
+                mocked: false,
+                stale: false,
+            };
+            drop(entry);
+            computation.query = None;
+        } else if !computation.invalidated.load(Ordering::Relaxed) {
+            // Threads already waiting get this result, later fetches compute
+            // their own.
+            let _ = computation.handoff.set((result, dependencies));
+        }
+        Self::report_progress(thread);
+        output
+    }
+
+    fn report_progress(thread: &ThreadState<DB::Query>) {
+        let progress = {
+            let mut progress = thread.progress.lock().unwrap();
+            progress.as_mut().map(|(completed, on_progress)| {
+                *completed += 1;
+                (*completed, on_progress.clone())
+            })
+        };
+        if let Some((completed, on_progress)) = progress {
+            on_progress(Progress { completed });
+        }
+    }
+
+    fn record_dependency(&self, thread: &ThreadState<DB::Query>, query: DB::Query) {
+        if thread.untracked.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut query_dependencies = thread.query_dependencies.lock().unwrap();
+        if !self.deduplicate_dependencies || !query_dependencies.contains(&query) {
+            query_dependencies.push(query);
+        }
+    }
+
+    pub fn fetch<Q: Query<DB>>(&self, query: &Q) -> Q::Result {
+        self.fetch_with(query, |result, _| result.clone()).0
+    }
+
+    pub fn fetch_traced<Q: Query<DB>>(&self, query: &Q) -> (Q::Result, FetchOutcome) {
+        self.fetch_with(query, |result, _| result.clone())
+    }
+
+    /// Like `fetch`, but also returns the dependencies recorded for the
+    /// result, read together with it so that a concurrent recomputation can't
+    /// pair the value with another computation's dependencies.
+    pub fn fetch_with_deps<Q: Query<DB>>(&self, query: &Q) -> (Q::Result, Vec<DB::Query>) {
+        self.fetch_with(query, |result, dependencies| {
+            (result.clone(), dependencies.to_vec())
+        })
+        .0
+    }
+
+    pub fn fetch_materialized<Q: Query<DB>>(&self, query: &Q) -> <Q::Result as Materialize>::Output
+    where
+        Q::Result: Materialize,
+    {
+        self.fetch_with(query, |result, _| result.materialize()).0
+    }
+
+    /// Like `fetch`, but returns a panic in the rule or one of its dependencies,
+    /// including a detected cycle, as an error. Other payloads, such as
+    /// `BudgetExceeded`, keep unwinding. A rule that catches a panic this way
+    /// isn't cached, since its result is degraded, so the next fetch runs it
+    /// again. Rules that fetched the degraded result are cached as usual.
+    pub fn fetch_catch<Q: Query<DB>>(&self, query: &Q) -> Result<Q::Result, RulePanic> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.fetch(query))).map_err(
+            |payload| {
+                let rule_panic = RulePanic::new(payload);
+                self.do_not_cache();
+                rule_panic
+            },
+        )
+    }
+
+    /// Like `fetch_catch`, but falls back to the default result if the rule or
+    /// one of its dependencies panics, including on a cycle. The calling rule
+    /// is then not cached, as with `fetch_catch`.
+    pub fn fetch_or_default<Q: Query<DB>>(&self, query: &Q) -> Q::Result
+    where
+        Q::Result: Default,
+    {
+        self.fetch_catch(query).unwrap_or_default()
+    }
+
+    /// Like `fetch`, but gives up with `BudgetExceeded` once this thread has
+    /// started `max_rules` rules on its behalf. Results completed before that
+    /// stay cached, and queries left unfinished are cleared for other threads
+    /// to compute. Rules that other threads run while this one waits are not
+    /// counted. `fetch_catch` and `fetch_or_default` in the rules in between
+    /// don't stop the abort.
+    pub fn fetch_budgeted<Q: Query<DB>>(
+        &self,
+        query: &Q,
+        max_rules: usize,
+    ) -> Result<Q::Result, BudgetExceeded> {
+        let thread = self.thread_state();
+        let budget = (max_rules as u64).min(thread.rule_budget.load(Ordering::Relaxed));
+        let _budget = Restore::set(&thread.rule_budget, budget);
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.fetch(query))).map_err(
+            |payload| match payload.downcast::<BudgetExceeded>() {
+                Ok(budget_exceeded) => *budget_exceeded,
+                Err(payload) => std::panic::resume_unwind(payload),
+            },
+        )
+    }
+
+    /// Runs `f` with a scope for spawning threads that fetch from this context,
+    /// like `std::thread::scope`. Queries fetched on the spawned threads are
+    /// recorded as dependencies of the current rule once all of them have
+    /// finished. The spawned threads must not fetch a query that the calling
+    /// thread is computing, since it can't make progress until they return.
+    pub fn scope<'env, R>(
+        &'env self,
+        f: impl for<'scope> FnOnce(&Scope<'scope, 'env, DB>) -> R,
+    ) -> R {
+        let dependencies = Arc::new(Mutex::new(Vec::new()));
+        let thread = self.thread_state();
+        let uncached = thread.uncached.load(Ordering::Relaxed);
+        let result = std::thread::scope(|scope| {
+            f(&Scope {
+                context: self,
+                scope,
+                uncached,
+                dependencies: dependencies.clone(),
+            })
+        });
+        for dependency in std::mem::take(&mut *dependencies.lock().unwrap()) {
+            self.record_dependency(&thread, dependency);
+        }
+        result
+    }
+
+    /// Like `fetch`, but makes `meta` available through `request_meta` to the
+    /// rules this thread runs until the fetch returns. Rules run by other
+    /// threads see their own thread's meta, and cached results are shared
+    /// regardless of the meta they were computed under.
+    pub fn fetch_with_meta<Q: Query<DB>>(
+        &self,
+        query: &Q,
+        meta: impl Any + Send + Sync,
+    ) -> Q::Result {
+        let thread = self.thread_state();
+        let _meta = Restore::set(&thread.request_meta, Some(Arc::new(meta)));
+        self.fetch(query)
+    }
+
+    /// Like `fetch`, but calls `on_progress` with a running count each time a
+    /// rule this thread runs for the fetch completes. The total isn't known
+    /// up front, and cached dependencies and rules run by other threads are
+    /// not counted, even when this thread waits on them. Unrelated work this
+    /// thread steals while waiting is not counted either.
+    pub fn fetch_with_progress<Q: Query<DB>>(
+        &self,
+        query: &Q,
+        on_progress: impl Fn(Progress) + Send + Sync + 'static,
+    ) -> Q::Result {
+        let thread = self.thread_state();
+        let _progress = Restore::set(&thread.progress, Some((0, Arc::new(on_progress))));
+        self.fetch(query)
+    }
+
+    pub fn request_meta<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let meta = self.thread_state().request_meta.lock().unwrap().clone()?;
+        meta.downcast().ok()
+    }
+
+    fn fetch_with<Q: Query<DB>, T>(
+        &self,
+        query: &Q,
+        read: impl Fn(&Q::Result, &[DB::Query]) -> T,
+    ) -> (T, FetchOutcome) {
+        let thread = self.thread_state();
+        #[cfg(feature = "backtrace")]
+        if thread.rule_depth.load(Ordering::Relaxed) > 0 {
+            let backtrace = std::backtrace::Backtrace::force_capture();
+            return std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                self.fetch_untraced(&thread, query, &read)
+            }))
+            .unwrap_or_else(|payload| {
+                let mut query_panic = QueryPanic::new(payload);
+                query_panic.backtraces.push(format!(
+                    "{:?} fetched at:\n{backtrace}",
+                    query.clone().into()
+                ));
+                std::panic::resume_unwind(query_panic)
+            });
+        }
+        self.fetch_untraced(&thread, query, &read)
+    }
+
+    fn fetch_untraced<Q: Query<DB>, T>(
+        &self,
+        thread: &ThreadState<DB::Query>,
+        query: &Q,
+        read: &impl Fn(&Q::Result, &[DB::Query]) -> T,
+    ) -> (T, FetchOutcome) {
+        self.record_dependency(thread, query.clone().into());
+        if thread.uncached.load(Ordering::Relaxed) {
+            let computed = self.rule(thread, query, 0);
+            return (
+                read(&computed.result, &computed.dependencies),
+                FetchOutcome::FreshComputed,
+            );
+        }
+        loop {
+            match self.try_fetch(thread, query.clone(), read) {
+                TryFetch::Stole(stealable) => {
+                    let started = Instant::now();
+                    self.steal(stealable);
+                    thread.exclude_time_since(started);
+                }
+                TryFetch::WaitFor(waiter, handoff) => {
+                    let _waiting = Waiting::enter(self, &waiter);
+                    let started = Instant::now();
+                    self.wait_strategy.wait(&waiter);
+                    thread.exclude_time_since(started);
+                    if let Some((result, dependencies)) = handoff.get() {
+                        return (read(result, dependencies), FetchOutcome::HandedOff);
+                    }
+                }
+                TryFetch::Complete(result, outcome) => return (result, outcome),
+            }
+        }
+    }
+
+    /// Runs the rule for `query` without consulting or populating the cache, and
+    /// likewise for every fetch it makes. Cyclic queries recurse without bound.
+    pub fn compute_uncached<Q: Query<DB>>(&self, query: &Q) -> Q::Result {
+        let thread = self.thread_state();
+        let _uncached = Restore::set(&thread.uncached, true);
+        self.rule(&thread, query, 0).result
+    }
+
+    /// The stored results of `Q` that a fetch would return without recomputing
+    /// them, so expired and invalidated entries are left out.
+    pub fn iter_complete<Q: Query<DB>>(&self) -> impl Iterator<Item = (Q, Q::Result)> + use<Q, DB> {
+        Q::storage(&self.database)
+            .iter()
+            .filter_map(|entry| match entry.value() {
+                Entry::Complete {
+                    result,
+                    computed_at,
+                    mocked,
+                    stale,
+                    ..
+                } if is_fresh::<DB, Q>(computed_at, *mocked, *stale) => {
+                    Some((entry.key().clone(), result.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The dependencies recorded for `query`, if its stored result is one a
+    /// fetch would return without recomputing it.
+    pub fn dependencies_of<Q: Query<DB>>(&self, query: &Q) -> Option<Vec<DB::Query>> {
+        match Q::storage(&self.database).get(query)?.value() {
             Entry::Complete {
-                result: result.clone(),
                 dependencies,
+                computed_at,
+                mocked,
+                stale,
+                ..
+            } if is_fresh::<DB, Q>(computed_at, *mocked, *stale) => Some(dependencies.clone()),
+            _ => None,
+        }
+    }
+
+    /// Queues `task` for a thread that would otherwise be waiting on a query
+    /// in progress, waking one such thread if any. Tasks nobody picks up are
+    /// run by `quiesce`. A panic in a task stops only that task.
+    pub fn spawn(&self, task: impl FnOnce(&Context<DB>) + Send + 'static) {
+        self.push_stealable(Stealable::Task(Box::new(task)));
+    }
+
+    /// Computes every query in `queries` that isn't already stored, handing
+    /// them out to threads waiting in this context and working through the
+    /// rest on the calling thread. Returns once the context is quiescent, so
+    /// the same caveats as for `quiesce` apply.
+    pub fn compute_all<Q: Query<DB>>(&self, queries: impl IntoIterator<Item = Q>) {
+        for query in queries {
+            self.push_stealable(Stealable::Query(query.into()));
+        }
+        self.quiesce();
+    }
+
+    /// Offers `queries` to threads waiting in this context, so that they may
+    /// be stored by the time the current rule fetches them. Hinted queries
+    /// become dependencies only if the rule actually fetches them.
+    pub fn hint_dependencies(&self, queries: impl IntoIterator<Item = DB::Query>) {
+        for query in queries {
+            self.push_stealable(Stealable::Query(query));
+        }
+    }
+
+    /// Blocks until no query is being computed and no stealable work is left.
+    /// Must not be called from within a rule, since that rule's own computation
+    /// never finishes while it waits.
+    pub fn quiesce(&self) {
+        loop {
+            while let Some(stealable) = self.pop_stealable() {
+                self.steal(stealable);
+            }
+            let mut active = self.computations.active.lock().unwrap();
+            while *active > 0 {
+                active = self.computations.quiescent.wait(active).unwrap();
+            }
+            drop(active);
+            if self.stealable.lock().unwrap().work.is_empty() {
+                return;
+            }
+        }
+    }
+
+    /// Called from within a rule to keep its result out of the cache. The
+    /// caller and threads already waiting on the query still get the value,
+    /// but the next fetch computes it again.
+    pub fn do_not_cache(&self) {
+        self.thread_state()
+            .do_not_cache
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// Called from within a rule, the number of times the query it computes
+    /// has been computed before, e.g. because a cached result outlived its
+    /// `TTL` or was invalidated. Zero on the first computation and outside any
+    /// rule.
+    pub fn recompute_count(&self) -> u64 {
+        self.thread_state().recompute_count.load(Ordering::Relaxed)
+    }
+
+    /// How often threads waiting on a query in progress looked for other work,
+    /// how many stolen items got computed, how often there was nothing to
+    /// steal so the thread waited, and how many stolen queries were already
+    /// stored by the time they were taken. Steals made by `quiesce` and
+    /// `compute_all` are counted as successful or wasted too.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            steal_attempts: self.steal_counters.attempts.load(Ordering::Relaxed),
+            successful_steals: self.steal_counters.successful.load(Ordering::Relaxed),
+            empty_steals: self.steal_counters.empty.load(Ordering::Relaxed),
+            wasted_steals: self.steal_counters.wasted.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn owner_of<Q: Query<DB>>(&self, query: &Q) -> Option<ThreadId> {
+        match Q::storage(&self.database).get(query)?.value() {
+            Entry::InProgress { thread_id, .. } => Some(*thread_id),
+            Entry::Complete { .. } => None,
+        }
+    }
+
+    /// Marks every completed entry of `Q` stale so the next fetch of each
+    /// reruns its rule, which counts as a recompute. Entries still in progress
+    /// are kept so their waiters are woken as usual, but their results aren't
+    /// cached, and waiters compute the query again. Mocked entries are left
+    /// alone. Queries of other types that depended on the stale entries keep
+    /// their cached results.
+    pub fn invalidate_type<Q: Query<DB>>(&self) {
+        for mut entry in Q::storage(&self.database).iter_mut() {
+            match entry.value_mut() {
+                Entry::InProgress { invalidated, .. } => invalidated.store(true, Ordering::Relaxed),
+                Entry::Complete { mocked, stale, .. } => *stale = !*mocked,
+            }
+        }
+    }
+
+    /// Stores `result` for `query` as if its rule had returned it, so that
+    /// fetches get it without running the rule until `unmock` is called.
+    /// Mocked entries don't expire and survive `invalidate_type`. A mock of a
+    /// query in progress replaces it, and the computation's result only goes
+    /// to itself. Intended for testing code built on the database.
+    pub fn mock<Q: Query<DB>>(&self, query: Q, result: Q::Result) {
+        Q::storage(&self.database).insert(
+            query,
+            Entry::Complete {
+                result,
+                dependencies: Vec::new(),
+                computed_at: Instant::now(),
+                recompute_count: 0,
+                compute_time: Duration::ZERO,
+                mocked: true,
+                stale: false,
             },
         );
-        for (waiting_thread_id, waiter) in waiters.borrow().iter() {
-            self.thread_dependencies.remove(waiting_thread_id);
-            waiter.unpark();
+    }
+
+    pub fn unmock<Q: Query<DB>>(&self, query: &Q) {
+        Q::storage(&self.database).remove_if(query, |_, entry| {
+            matches!(entry, Entry::Complete { mocked: true, .. })
+        });
+    }
+
+    /// The longest chain of stored dependencies from `query` down to a query
+    /// with none, starting with `query` itself. Queries that haven't been
+    /// computed count as leaves.
+    pub fn critical_path<Q: Query<DB>>(&self, query: &Q) -> Vec<DB::Query> {
+        let query = query.clone().into();
+        let mut longest = FxHashMap::default();
+        self.longest_path(query.clone(), &mut longest);
+        let mut path = Vec::new();
+        let mut next = Some(query);
+        while let Some(query) = next {
+            next = longest[&query].1.clone();
+            path.push(query);
         }
-        TryFetch::Complete(result)
+        path
     }
 
-    pub fn fetch<Q: Query<DB>>(&self, query: &Q) -> Q::Result {
-        if !self
-            .query_dependencies
-            .borrow()
-            .contains(&query.clone().into())
+    fn longest_path(
+        &self,
+        query: DB::Query,
+        longest: &mut FxHashMap<DB::Query, (usize, Option<DB::Query>)>,
+    ) -> usize {
+        if let Some((length, _)) = longest.get(&query) {
+            return *length;
+        }
+        longest.insert(query.clone(), (1, None));
+        let mut best = (0, None);
+        for dependency in
+            DB::dispatch(DependenciesOf { context: self }, query.clone()).unwrap_or_default()
         {
-            self.query_dependencies
-                .borrow_mut()
-                .push(query.clone().into());
+            let length = self.longest_path(dependency.clone(), longest);
+            if length > best.0 {
+                best = (length, Some(dependency));
+            }
         }
-        loop {
-            match self.try_fetch(query.clone()) {
-                TryFetch::Stole(stealable) => self.steal(stealable),
-                TryFetch::WaitFor(parker) => {
-                    self.thieves
-                        .borrow_mut()
-                        .push_back((std::thread::current().id(), parker.unparker().clone()));
-                    parker.park();
-                    self.thieves
-                        .borrow_mut()
-                        .retain(|(tid, _)| *tid != std::thread::current().id());
+        longest.insert(query, (best.0 + 1, best.1));
+        best.0 + 1
+    }
+
+    /// The leaves of the stored dependency graph below `query`, i.e. the
+    /// queries it transitively depends on that have no dependencies of their
+    /// own, in the order they are first reached. Queries that haven't been
+    /// computed count as leaves, so `query` should be fetched first.
+    pub fn reachable_inputs<Q: Query<DB>>(&self, query: &Q) -> Vec<DB::Query> {
+        let query = query.clone().into();
+        let mut visited = FxHashMap::default();
+        let mut inputs = Vec::new();
+        let mut stack = vec![query.clone()];
+        visited.insert(query, ());
+        while let Some(query) = stack.pop() {
+            let dependencies =
+                DB::dispatch(DependenciesOf { context: self }, query.clone()).unwrap_or_default();
+            if dependencies.is_empty() {
+                inputs.push(query);
+                continue;
+            }
+            for dependency in dependencies.into_iter().rev() {
+                if visited.insert(dependency.clone(), ()).is_none() {
+                    stack.push(dependency);
                 }
-                TryFetch::Complete(result) => return result,
             }
         }
+        inputs
+    }
+
+    /// The queries `query` transitively depends on according to the stored
+    /// dependency graph, each yielded once in depth-first order. The graph is
+    /// walked as the iterator is advanced, so stopping early skips reading the
+    /// dependencies of the rest. Nothing is computed; queries that haven't
+    /// been computed are yielded without dependencies.
+    pub fn dependency_closure<Q: Query<DB>>(&self, query: &Q) -> impl Iterator<Item = DB::Query> {
+        let query = query.clone().into();
+        let mut visited = FxHashMap::default();
+        let mut stack = vec![query.clone()];
+        visited.insert(query, ());
+        let mut root = true;
+        std::iter::from_fn(move || {
+            loop {
+                let query = stack.pop()?;
+                let dependencies = DB::dispatch(DependenciesOf { context: self }, query.clone())
+                    .unwrap_or_default();
+                for dependency in dependencies.into_iter().rev() {
+                    if visited.insert(dependency.clone(), ()).is_none() {
+                        stack.push(dependency);
+                    }
+                }
+                if !std::mem::take(&mut root) {
+                    return Some(query);
+                }
+            }
+        })
+    }
+
+    /// Panics with the offending chain if the stored dependency graph below
+    /// `query` contains a cycle. Intended for tests.
+    pub fn assert_acyclic<Q: Query<DB>>(&self, query: &Q) {
+        let mut visited = FxHashMap::default();
+        let mut path = Vec::new();
+        self.visit_acyclic(query.clone().into(), &mut visited, &mut path);
+    }
+
+    fn visit_acyclic(
+        &self,
+        query: DB::Query,
+        visited: &mut FxHashMap<DB::Query, bool>,
+        path: &mut Vec<DB::Query>,
+    ) {
+        match visited.get(&query) {
+            Some(true) => {
+                let start = path.iter().position(|other| *other == query).unwrap();
+                let cycle = path[start..]
+                    .iter()
+                    .chain([&query])
+                    .map(|query| format!("{query:?}"))
+                    .collect::<Vec<_>>();
+                panic!("cyclic dependencies: {}", cycle.join(" -> "));
+            }
+            Some(false) => return,
+            None => {}
+        }
+        visited.insert(query.clone(), true);
+        path.push(query.clone());
+        for dependency in
+            DB::dispatch(DependenciesOf { context: self }, query.clone()).unwrap_or_default()
+        {
+            self.visit_acyclic(dependency, visited, path);
+        }
+        path.pop();
+        visited.insert(query, false);
+    }
+
+    pub fn reduce<Q: Query<DB>, M: Monoid + From<Q::Result>>(&self, children: &[Q]) -> M {
+        children.iter().fold(M::empty(), |accumulated, child| {
+            accumulated.combine(M::from(self.fetch(child)))
+        })
+    }
+
+    /// Runs `f` without recording the queries it fetches as dependencies of
+    /// the current rule.
+    ///
+    /// This is unsound for anything that affects the rule's result: if an
+    /// untracked query later changes, the rule is not invalidated and keeps
+    /// returning a stale value. Use it only for heuristics, logging and similar
+    /// reads that must not influence correctness.
+    pub fn untracked<R>(&self, f: impl FnOnce(&Context<DB>) -> R) -> R {
+        let thread = self.thread_state();
+        let _untracked = Restore::set(&thread.untracked, true);
+        f(self)
+    }
+
+    /// A handle that doesn't keep the context alive, for background work that
+    /// should stop once every `Arc` to the context has been dropped.
+    pub fn downgrade(self: &Arc<Self>) -> WeakContext<DB> {
+        WeakContext {
+            context: Arc::downgrade(self),
+        }
+    }
+
+    /// A context over the same database that records dependencies and steals
+    /// work independently of this one. Entries computed through either context
+    /// are visible to both.
+    pub fn fork(&self) -> Context<DB> {
+        Context {
+            threads: DashMap::new(),
+            stealable: Mutex::default(),
+            thieves: Mutex::new(VecDeque::new()),
+            database: self.database.clone(),
+            thread_dependencies: self.thread_dependencies.clone(),
+            wait_strategy: self.wait_strategy.clone(),
+            deduplicate_dependencies: self.deduplicate_dependencies,
+            computations: self.computations.clone(),
+            steal_counters: StealCounters::default(),
+        }
+    }
+}
+
+impl<DB: HasRegistry> Context<DB> {
+    /// The `n` stored queries of any registered type that have been recomputed
+    /// most often, with their recompute count and the total time spent in
+    /// their rules, including fetches made from them. Time spent waiting on
+    /// other threads, or on work stolen while waiting, is not counted. Ties are
+    /// broken by time.
+    pub fn hottest(&self, n: usize) -> Vec<(DB::Query, u64, Duration)> {
+        let mut hottest = self.database.registry().hottest();
+        hottest.sort_by(|(_, count1, time1), (_, count2, time2)| {
+            (count2, time2).cmp(&(count1, time1))
+        });
+        hottest.truncate(n);
+        hottest
+    }
+
+    /// Checks the stored entries of every registered type and the context's
+    /// own bookkeeping for inconsistencies, returning a description of each
+    /// one found. Only meaningful when no fetch is running. Intended for tests.
+    pub fn check_invariants(&self) -> Result<(), Vec<String>> {
+        let mut violations = Vec::new();
+        let active = *self.computations.active.lock().unwrap();
+        if active != 0 {
+            violations.push(format!("{active} computations are still counted as active"));
+        }
+        if !self.threads.is_empty() {
+            violations.push(format!(
+                "{} threads still hold state in the context",
+                self.threads.len()
+            ));
+        }
+        for entry in self.thread_dependencies.iter() {
+            violations.push(format!(
+                "{:?} is still recorded as waiting on {:?}",
+                entry.key(),
+                entry.value().0
+            ));
+        }
+        self.database
+            .registry()
+            .check_invariants(self.deduplicate_dependencies, &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Copies the completed entries of `other` whose query satisfies
+    /// `predicate` into this context's database, for query types registered
+    /// in both. Mocked and invalidated entries are not copied. Entries this
+    /// database already has are left alone, and copied entries keep their
+    /// age, so a `TTL` still expires them on schedule.
+    pub fn prewarm_from(&self, other: &Context<DB>, predicate: impl Fn(&DB::Query) -> bool) {
+        if Arc::ptr_eq(&self.database, &other.database) {
+            return;
+        }
+        self.database
+            .registry()
+            .prewarm_from(other.database.registry(), &predicate);
+    }
+
+    /// Every completed query of a registered type with its dependencies, one
+    /// per line, sorted so that the output doesn't depend on scheduling or
+    /// map layout. Results are not included. Intended for golden tests.
+    pub fn dump_sorted(&self) -> String
+    where
+        DB::Query: Ord,
+    {
+        let mut complete = self.database.registry().complete();
+        complete.sort();
+        let mut out = String::new();
+        for (query, mut dependencies) in complete {
+            dependencies.sort();
+            out.push_str(&format!("{query:?}: {dependencies:?}\n"));
+        }
+        out
     }
 }
@@ -0,0 +1,208 @@
+use crate::{Database, Entry, FxDashMap, Query};
+use fxhash::{FxBuildHasher, FxHashMap};
+use std::{
+    any::{Any, TypeId},
+    fmt::Write,
+    time::Duration,
+};
+
+trait Storage<DB: Database>: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn dump_dot(&self, out: &mut String);
+    fn hottest(&self, out: &mut Vec<(DB::Query, u64, Duration)>);
+    fn check_invariants(&self, deduplicated: bool, violations: &mut Vec<String>);
+    fn complete(&self, out: &mut Vec<(DB::Query, Vec<DB::Query>)>);
+    fn prewarm_from(&self, other: &dyn Storage<DB>, predicate: &dyn Fn(&DB::Query) -> bool);
+}
+
+impl<DB, Q> Storage<DB> for FxDashMap<Q, Entry<Q::Result, DB::Query>>
+where
+    DB: Database + 'static,
+    Q: Query<DB> + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn dump_dot(&self, out: &mut String) {
+        for entry in self.iter() {
+            let node = format!("{:?}", entry.key().clone().into());
+            writeln!(out, "    {node:?};").unwrap();
+            if let Entry::Complete { dependencies, .. } = entry.value() {
+                for dependency in dependencies {
+                    writeln!(out, "    {node:?} -> {:?};", format!("{dependency:?}")).unwrap();
+                }
+            }
+        }
+    }
+
+    fn hottest(&self, out: &mut Vec<(DB::Query, u64, Duration)>) {
+        for entry in self.iter() {
+            if let Entry::Complete {
+                recompute_count,
+                compute_time,
+                ..
+            } = entry.value()
+            {
+                out.push((entry.key().clone().into(), *recompute_count, *compute_time));
+            }
+        }
+    }
+
+    fn complete(&self, out: &mut Vec<(DB::Query, Vec<DB::Query>)>) {
+        for entry in self.iter() {
+            if let Entry::Complete {
+                dependencies,
+                stale: false,
+                ..
+            } = entry.value()
+            {
+                out.push((entry.key().clone().into(), dependencies.clone()));
+            }
+        }
+    }
+
+    fn prewarm_from(&self, other: &dyn Storage<DB>, predicate: &dyn Fn(&DB::Query) -> bool) {
+        let other = other.as_any().downcast_ref::<Self>().unwrap();
+        for entry in other.iter() {
+            if matches!(
+                entry.value(),
+                Entry::Complete {
+                    mocked: false,
+                    stale: false,
+                    ..
+                }
+            ) && predicate(&entry.key().clone().into())
+            {
+                self.entry(entry.key().clone())
+                    .or_insert_with(|| entry.value().clone());
+            }
+        }
+    }
+
+    fn check_invariants(&self, deduplicated: bool, violations: &mut Vec<String>) {
+        for entry in self.iter() {
+            let query = entry.key().clone().into();
+            match entry.value() {
+                Entry::InProgress { .. } => violations.push(format!(
+                    "{query:?} is in progress with nothing computing it"
+                )),
+                Entry::Complete { dependencies, .. } => {
+                    if deduplicated {
+                        for (i, dependency) in dependencies.iter().enumerate() {
+                            if dependencies[..i].contains(dependency) {
+                                violations.push(format!(
+                                    "{query:?} records {dependency:?} as a dependency twice"
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub trait HasRegistry: Database + 'static {
+    fn registry(&self) -> &Registry<Self>;
+}
+
+pub struct Registry<DB: Database> {
+    storages: FxHashMap<TypeId, Box<dyn Storage<DB>>>,
+}
+
+impl<DB: Database + 'static> Registry<DB> {
+    pub fn storage<Q: Query<DB> + 'static>(&self) -> &FxDashMap<Q, Entry<Q::Result, DB::Query>> {
+        self.storages
+            .get(&TypeId::of::<Q>())
+            .and_then(|storage| storage.as_any().downcast_ref())
+            .unwrap_or_else(|| panic!("query {} is not registered", std::any::type_name::<Q>()))
+    }
+
+    pub fn dump_dot(&self) -> String {
+        let mut out = String::from("digraph {\n");
+        for storage in self.storages.values() {
+            storage.dump_dot(&mut out);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub(crate) fn check_invariants(&self, deduplicated: bool, violations: &mut Vec<String>) {
+        for storage in self.storages.values() {
+            storage.check_invariants(deduplicated, violations);
+        }
+    }
+
+    pub(crate) fn prewarm_from(
+        &self,
+        other: &Registry<DB>,
+        predicate: &dyn Fn(&DB::Query) -> bool,
+    ) {
+        for (type_id, storage) in &self.storages {
+            if let Some(other_storage) = other.storages.get(type_id) {
+                storage.prewarm_from(other_storage.as_ref(), predicate);
+            }
+        }
+    }
+
+    pub(crate) fn complete(&self) -> Vec<(DB::Query, Vec<DB::Query>)> {
+        let mut out = Vec::new();
+        for storage in self.storages.values() {
+            storage.complete(&mut out);
+        }
+        out
+    }
+
+    pub(crate) fn hottest(&self) -> Vec<(DB::Query, u64, Duration)> {
+        let mut out = Vec::new();
+        for storage in self.storages.values() {
+            storage.hottest(&mut out);
+        }
+        out
+    }
+}
+
+pub struct DatabaseBuilder<DB: Database> {
+    registry: Registry<DB>,
+}
+
+impl<DB: Database + 'static> Default for DatabaseBuilder<DB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<DB: Database + 'static> DatabaseBuilder<DB> {
+    pub fn new() -> Self {
+        DatabaseBuilder {
+            registry: Registry {
+                storages: FxHashMap::default(),
+            },
+        }
+    }
+
+    pub fn register<Q: Query<DB> + 'static>(self) -> Self {
+        self.register_with_capacity::<Q>(0)
+    }
+
+    pub fn register_with_capacity<Q: Query<DB> + 'static>(mut self, capacity: usize) -> Self {
+        self.registry.storages.insert(
+            TypeId::of::<Q>(),
+            Box::new(
+                FxDashMap::<Q, Entry<Q::Result, DB::Query>>::with_capacity_and_hasher(
+                    capacity,
+                    FxBuildHasher::default(),
+                ),
+            ),
+        );
+        self
+    }
+
+    pub fn build(self) -> DB
+    where
+        DB: From<Registry<DB>>,
+    {
+        DB::from(self.registry)
+    }
+}
@@ -0,0 +1,91 @@
+use crossbeam::sync::{Parker, Unparker};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+pub trait WaitStrategy: Send + Sync {
+    fn wait(&self, waiter: &Waiter);
+}
+
+pub struct Waiter {
+    parker: Parker,
+    woken: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+pub struct Wakeup {
+    unparker: Unparker,
+    woken: Arc<AtomicBool>,
+}
+
+impl Waiter {
+    pub(crate) fn new() -> Self {
+        Waiter {
+            parker: Parker::new(),
+            woken: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn wakeup(&self) -> Wakeup {
+        Wakeup {
+            unparker: self.parker.unparker().clone(),
+            woken: self.woken.clone(),
+        }
+    }
+
+    pub fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    pub fn park(&self) {
+        while !self.is_woken() {
+            self.parker.park();
+        }
+    }
+}
+
+impl Wakeup {
+    pub(crate) fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn wake(&self) {
+        self.woken.store(true, Ordering::Release);
+        self.unparker.unpark();
+    }
+}
+
+pub struct Park;
+
+impl WaitStrategy for Park {
+    fn wait(&self, waiter: &Waiter) {
+        waiter.park();
+    }
+}
+
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+    fn wait(&self, waiter: &Waiter) {
+        while !waiter.is_woken() {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+pub struct YieldThenPark {
+    pub yields: usize,
+}
+
+impl WaitStrategy for YieldThenPark {
+    fn wait(&self, waiter: &Waiter) {
+        for _ in 0..self.yields {
+            if waiter.is_woken() {
+                return;
+            }
+            std::thread::yield_now();
+        }
+        waiter.park();
+    }
+}
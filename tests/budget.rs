@@ -0,0 +1,40 @@
+mod common;
+
+use common::{Node, context};
+use rockrs::BudgetExceeded;
+
+fn chain(context: &rockrs::Context<common::Db>, length: u32) {
+    for i in 1..=length {
+        context
+            .database()
+            .set_rule(Node(i), move |c| c.fetch(&Node(i - 1)) + 1);
+    }
+}
+
+#[test]
+fn budget_aborts_and_leaves_database_clean() {
+    let context = context();
+    chain(&context, 100);
+    assert!(matches!(
+        context.fetch_budgeted(&Node(100), 50),
+        Err(BudgetExceeded)
+    ));
+    assert_eq!(context.dependencies_of(&Node(100)), None);
+    assert_eq!(context.check_invariants(), Ok(()));
+    assert_eq!(context.fetch_budgeted(&Node(100), 101).unwrap(), 100);
+}
+
+#[test]
+fn fetch_or_default_does_not_swallow_budget_exceeded() {
+    let context = context();
+    chain(&context, 99);
+    context
+        .database()
+        .set_rule(Node(100), |c| c.fetch_or_default(&Node(99)) + 1);
+    assert!(matches!(
+        context.fetch_budgeted(&Node(100), 50),
+        Err(BudgetExceeded)
+    ));
+    assert_eq!(context.dependencies_of(&Node(100)), None);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
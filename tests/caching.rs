@@ -0,0 +1,199 @@
+mod common;
+
+use common::{Expiring, Node, Other, context, sleep, wait_until};
+use rockrs::FetchOutcome;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+#[test]
+fn mock_of_a_query_in_progress_survives_its_completion() {
+    let context = context();
+    let db = context.database();
+    let release = Arc::new(AtomicBool::new(false));
+    let released = release.clone();
+    db.set_rule(Node(1), move |_| {
+        wait_until(|| released.load(Ordering::Relaxed));
+        1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        context.mock(Node(1), 100);
+        release.store(true, Ordering::Relaxed);
+        assert_eq!(computing.join().unwrap(), 1);
+    });
+    assert_eq!(context.fetch(&Node(1)), 100);
+    assert_eq!(db.runs(Node(1)), 1);
+}
+
+#[test]
+fn mock_of_a_query_in_progress_survives_its_panic() {
+    let context = context();
+    let db = context.database();
+    let release = Arc::new(AtomicBool::new(false));
+    let released = release.clone();
+    db.set_rule(Node(1), move |_| {
+        wait_until(|| released.load(Ordering::Relaxed));
+        panic!("rule failed")
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        context.mock(Node(1), 100);
+        release.store(true, Ordering::Relaxed);
+        assert!(computing.join().is_err());
+    });
+    assert_eq!(context.fetch(&Node(1)), 100);
+    assert_eq!(db.runs(Node(1)), 1);
+}
+
+#[test]
+fn mocked_query_does_not_run_its_rule() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)) + 1);
+    context.mock(Node(2), 100);
+    assert_eq!(context.fetch(&Node(1)), 101);
+    assert_eq!(db.runs(Node(2)), 0);
+    context.unmock(&Node(2));
+    assert_eq!(context.fetch(&Node(2)), 2);
+}
+
+#[test]
+fn invalidate_type_counts_the_rerun_as_a_recompute() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.recompute_count());
+    assert_eq!(context.fetch(&Node(1)), 0);
+    context.invalidate_type::<Node>();
+    assert_eq!(context.fetch(&Node(1)), 1);
+    assert_eq!(db.runs(Node(1)), 2);
+    assert_eq!(context.hottest(1)[0].1, 1);
+}
+
+#[test]
+fn invalidate_type_leaves_other_types_cached() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Other(1), |c| c.fetch(&Node(1)) + 1);
+    context.fetch(&Other(1));
+    context.invalidate_type::<Node>();
+    assert_eq!(context.fetch(&Other(1)), 2);
+    assert_eq!(context.fetch(&Node(1)), 1);
+    assert_eq!(db.runs(Other(1)), 1);
+    assert_eq!(db.runs(Node(1)), 2);
+}
+
+#[test]
+fn expired_entries_are_not_reported_as_complete() {
+    let context = context();
+    context
+        .database()
+        .set_rule(Expiring(1), |c| c.fetch(&Node(1)) + 1);
+    context.fetch(&Expiring(1));
+    assert_eq!(context.iter_complete::<Expiring>().count(), 1);
+    assert_eq!(
+        context.dependencies_of(&Expiring(1)),
+        Some(vec![Node(1).into()])
+    );
+    sleep(100);
+    assert_eq!(context.iter_complete::<Expiring>().count(), 0);
+    assert_eq!(context.dependencies_of(&Expiring(1)), None);
+}
+
+#[test]
+fn expired_entries_are_recomputed() {
+    let context = context();
+    context.fetch(&Expiring(1));
+    context.fetch(&Expiring(1));
+    assert_eq!(context.database().runs(Expiring(1)), 1);
+    sleep(100);
+    context.fetch(&Expiring(1));
+    assert_eq!(context.database().runs(Expiring(1)), 2);
+}
+
+#[test]
+fn do_not_cache_reruns_the_rule_on_the_next_fetch() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        c.do_not_cache();
+        1
+    });
+    assert_eq!(context.fetch(&Node(1)), 1);
+    assert_eq!(context.fetch(&Node(1)), 1);
+    assert_eq!(db.runs(Node(1)), 2);
+    context.fetch(&Node(2));
+    context.fetch(&Node(2));
+    assert_eq!(db.runs(Node(2)), 1);
+}
+
+#[test]
+fn waiters_get_an_uncached_result_handed_off() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        c.do_not_cache();
+        wait_until(|| c.stats().empty_steals > 0);
+        1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        assert_eq!(context.fetch_traced(&Node(1)), (1, FetchOutcome::HandedOff));
+        assert_eq!(computing.join().unwrap(), 1);
+    });
+    assert_eq!(db.runs(Node(1)), 1);
+    context.fetch(&Node(1));
+    assert_eq!(db.runs(Node(1)), 2);
+}
+
+#[test]
+fn prewarm_from_copies_computed_entries_but_not_mocks() {
+    let source = context();
+    source.fetch(&Node(1));
+    source.mock(Node(2), 100);
+    let context = context();
+    context.prewarm_from(&source, |_| true);
+    assert_eq!(context.fetch(&Node(1)), 1);
+    assert_eq!(context.fetch(&Node(2)), 2);
+    assert_eq!(context.database().runs(Node(1)), 0);
+}
+
+#[test]
+fn invalidate_type_reruns_every_query_of_the_type() {
+    let context = context();
+    let db = context.database();
+    for i in 1..=3 {
+        context.fetch(&Node(i));
+    }
+    context.invalidate_type::<Node>();
+    for i in 1..=3 {
+        assert_eq!(context.fetch(&Node(i)), i.into());
+        assert_eq!(db.runs(Node(i)), 2);
+    }
+}
+
+#[test]
+fn invalidate_type_reruns_a_query_in_progress() {
+    let context = context();
+    let db = context.database();
+    let release = Arc::new(AtomicBool::new(false));
+    let released = release.clone();
+    db.set_rule(Node(1), move |_| {
+        wait_until(|| released.load(Ordering::Relaxed));
+        1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        context.invalidate_type::<Node>();
+        release.store(true, Ordering::Relaxed);
+        assert_eq!(computing.join().unwrap(), 1);
+    });
+    assert_eq!(context.dependencies_of(&Node(1)), None);
+    context.fetch(&Node(1));
+    assert_eq!(db.runs(Node(1)), 2);
+}
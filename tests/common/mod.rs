@@ -0,0 +1,166 @@
+#![allow(dead_code)]
+
+use derive_more::From;
+use fxhash::FxBuildHasher;
+use rockrs::{Context, Database, DatabaseBuilder, Dispatch, Entry, HasRegistry, Query, Registry};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+type Storage<Q> = dashmap::DashMap<Q, Entry<u64, Key>, FxBuildHasher>;
+type Rule = Arc<dyn Fn(&Context<Db>) -> u64 + Send + Sync>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Node(pub u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Expiring(pub u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+pub struct Other(pub u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Ord, From)]
+pub enum Key {
+    Node(Node),
+    Expiring(Expiring),
+    Other(Other),
+}
+
+/// A database whose rules are set per query by the test. Queries without a
+/// rule return their own number.
+pub struct Db {
+    registry: Registry<Db>,
+    rules: Mutex<HashMap<Key, Rule>>,
+    runs: Mutex<HashMap<Key, usize>>,
+}
+
+impl Db {
+    pub fn set_rule(
+        &self,
+        query: impl Into<Key>,
+        rule: impl Fn(&Context<Db>) -> u64 + Send + Sync + 'static,
+    ) {
+        self.rules
+            .lock()
+            .unwrap()
+            .insert(query.into(), Arc::new(rule));
+    }
+
+    /// How many times the rule for `query` has started.
+    pub fn runs(&self, query: impl Into<Key>) -> usize {
+        self.runs
+            .lock()
+            .unwrap()
+            .get(&query.into())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn run(context: &Context<Db>, query: Key, number: u32) -> u64 {
+        let db = context.database();
+        *db.runs.lock().unwrap().entry(query).or_default() += 1;
+        let rule = db.rules.lock().unwrap().get(&query).cloned();
+        match rule {
+            Some(rule) => rule(context),
+            None => number.into(),
+        }
+    }
+}
+
+impl From<Registry<Db>> for Db {
+    fn from(registry: Registry<Db>) -> Self {
+        Db {
+            registry,
+            rules: Mutex::default(),
+            runs: Mutex::default(),
+        }
+    }
+}
+
+impl HasRegistry for Db {
+    fn registry(&self) -> &Registry<Self> {
+        &self.registry
+    }
+}
+
+impl Database for Db {
+    type Query = Key;
+
+    fn dispatch<D>(d: D, q: Key) -> D::Result
+    where
+        D: Dispatch<Self>,
+    {
+        match q {
+            Key::Node(node) => d.dispatch(node),
+            Key::Expiring(expiring) => d.dispatch(expiring),
+            Key::Other(other) => d.dispatch(other),
+        }
+    }
+}
+
+impl Query<Db> for Node {
+    type Result = u64;
+
+    fn storage(db: &Db) -> &Storage<Self> {
+        db.registry.storage()
+    }
+
+    fn rule(context: &Context<Db>, query: &Self) -> u64 {
+        Db::run(context, (*query).into(), query.0)
+    }
+}
+
+impl Query<Db> for Expiring {
+    type Result = u64;
+
+    const TTL: Option<Duration> = Some(Duration::from_millis(50));
+
+    fn storage(db: &Db) -> &Storage<Self> {
+        db.registry.storage()
+    }
+
+    fn rule(context: &Context<Db>, query: &Self) -> u64 {
+        Db::run(context, (*query).into(), query.0)
+    }
+}
+
+impl Query<Db> for Other {
+    type Result = u64;
+
+    fn storage(db: &Db) -> &Storage<Self> {
+        db.registry.storage()
+    }
+
+    fn rule(context: &Context<Db>, query: &Self) -> u64 {
+        Db::run(context, (*query).into(), query.0)
+    }
+}
+
+pub fn database() -> Db {
+    DatabaseBuilder::new()
+        .register::<Node>()
+        .register::<Expiring>()
+        .register::<Other>()
+        .build()
+}
+
+pub fn context() -> Context<Db> {
+    Context::new(database())
+}
+
+pub fn sleep(millis: u64) {
+    std::thread::sleep(Duration::from_millis(millis));
+}
+
+/// Polls until `condition` holds, failing the test after a few seconds.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    for _ in 0..5000 {
+        if condition() {
+            return;
+        }
+        sleep(1);
+    }
+    panic!("condition not reached");
+}
@@ -0,0 +1,159 @@
+mod common;
+
+use common::{Db, Expiring, Key, Node, Other, context, sleep, wait_until};
+use rockrs::Context;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+fn record_progress(context: &Context<Db>, query: Node) -> Vec<usize> {
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let recorded = counts.clone();
+    context.fetch_with_progress(&query, move |progress| {
+        recorded.lock().unwrap().push(progress.completed)
+    });
+    Arc::try_unwrap(counts).unwrap().into_inner().unwrap()
+}
+
+#[test]
+fn progress_counts_each_rule_once() {
+    let context = context();
+    let db = context.database();
+    for i in 2..=5 {
+        db.set_rule(Node(i), move |c| c.fetch(&Node(i - 1)) + 1);
+    }
+    assert_eq!(record_progress(&context, Node(5)), [1, 2, 3, 4, 5]);
+    assert!(record_progress(&context, Node(5)).is_empty());
+}
+
+#[test]
+fn progress_leaves_out_work_stolen_while_waiting() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)));
+    db.set_rule(Node(2), |c| {
+        c.hint_dependencies([Key::Node(Node(3))]);
+        wait_until(|| c.database().runs(Node(3)) > 0);
+        2
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(2)));
+        wait_until(|| context.owner_of(&Node(2)).is_some());
+        assert_eq!(record_progress(&context, Node(1)), [1]);
+        computing.join().unwrap();
+    });
+}
+
+#[test]
+fn a_query_recomputed_most_often_tops_hottest() {
+    let context = context();
+    context.fetch(&Node(1));
+    for _ in 0..3 {
+        context.fetch(&Expiring(1));
+        sleep(60);
+    }
+    context.fetch(&Expiring(2));
+    let hottest = context.hottest(2);
+    assert_eq!(hottest[0].0, Key::Expiring(Expiring(1)));
+    assert_eq!(hottest[0].1, 2);
+    assert_eq!(hottest.len(), 2);
+}
+
+#[test]
+fn hottest_leaves_out_time_spent_waiting() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)));
+    db.set_rule(Node(2), |_| {
+        sleep(100);
+        2
+    });
+    std::thread::scope(|s| {
+        s.spawn(|| context.fetch(&Node(2)));
+        wait_until(|| context.owner_of(&Node(2)).is_some());
+        context.fetch(&Node(1));
+    });
+    let hottest = context.hottest(2);
+    let time_of = |query: Key| hottest.iter().find(|(q, ..)| *q == query).unwrap().2;
+    assert!(time_of(Node(1).into()) < Duration::from_millis(50));
+    assert!(time_of(Node(2).into()) >= Duration::from_millis(100));
+}
+
+fn dump_after_fetching_with(threads: u32) -> String {
+    let context = context();
+    for i in 2..=40 {
+        context.database().set_rule(Node(i), move |c| {
+            c.hint_dependencies([Key::Node(Node(i / 2))]);
+            c.fetch(&Node(i - 1)) + c.fetch(&Node(i / 2))
+        });
+    }
+    std::thread::scope(|s| {
+        for thread in 0..threads {
+            let context = &context;
+            s.spawn(move || context.fetch(&Node(40 - thread)));
+        }
+    });
+    context.fetch(&Node(40));
+    context.quiesce();
+    context.dump_sorted()
+}
+
+#[test]
+fn dump_sorted_does_not_depend_on_scheduling() {
+    let dump = dump_after_fetching_with(1);
+    assert_eq!(dump.lines().count(), 40);
+    assert!(dump.starts_with("Node(Node(1)): []\nNode(Node(2)): [Node(Node(1))]\n"));
+    for threads in [2, 4, 8] {
+        assert_eq!(dump_after_fetching_with(threads), dump);
+    }
+}
+
+#[test]
+fn fetch_with_deps_returns_what_the_rule_fetched() {
+    let context = context();
+    context.database().set_rule(Node(1), |c| {
+        c.fetch(&Node(3)) + c.fetch(&Other(2)) + c.fetch(&Node(3))
+    });
+    let expected = vec![Key::Node(Node(3)), Key::Other(Other(2))];
+    assert_eq!(context.fetch_with_deps(&Node(1)), (8, expected.clone()));
+    assert_eq!(context.fetch_with_deps(&Node(1)), (8, expected));
+    assert_eq!(context.database().runs(Node(1)), 1);
+}
+
+fn chain(context: &Context<Db>, length: u32) {
+    for i in 2..=length {
+        context
+            .database()
+            .set_rule(Node(i), move |c| c.fetch(&Node(i - 1)) + c.fetch(&Node(1)));
+    }
+}
+
+#[test]
+fn dependency_closure_yields_each_dependency_once() {
+    let context = context();
+    chain(&context, 5);
+    context.fetch(&Node(5));
+    let mut closure: Vec<_> = context.dependency_closure(&Node(5)).collect();
+    closure.sort();
+    assert_eq!(
+        closure,
+        (1..5).map(|i| Key::Node(Node(i))).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn dependency_closure_reads_dependencies_only_as_it_advances() {
+    let context = context();
+    chain(&context, 5);
+    context.fetch(&Node(5));
+    let mut closure = context.dependency_closure(&Node(5));
+    assert_eq!(closure.next(), Some(Key::Node(Node(4))));
+    // Only the dependencies of Node(5) and Node(4) have been read, so
+    // invalidating the rest now cuts the walk short.
+    context.invalidate_type::<Node>();
+    assert_eq!(
+        closure.collect::<Vec<_>>(),
+        [Key::Node(Node(3)), Key::Node(Node(1))]
+    );
+}
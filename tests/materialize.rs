@@ -0,0 +1,101 @@
+use derive_more::From;
+use fxhash::FxBuildHasher;
+use rockrs::{Context, Database, Dispatch, Entry, Materialize, Query};
+use std::{ops::Deref, sync::Arc};
+
+type Storage<Q, R> = dashmap::DashMap<Q, Entry<R, Key>, FxBuildHasher>;
+
+/// The numbers from one up to the query's.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Numbers(usize);
+
+/// The even numbers among `Numbers` of the same bound.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Evens(usize);
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, From)]
+enum Key {
+    Numbers(Numbers),
+    Evens(Evens),
+}
+
+#[derive(Default)]
+struct Db {
+    numbers: Storage<Numbers, Shared>,
+    evens: Storage<Evens, Shared>,
+}
+
+/// A result stored once and shared by every caller.
+#[derive(Clone)]
+struct Shared(Arc<Vec<usize>>);
+
+impl Materialize for Shared {
+    type Output = View;
+
+    fn materialize(&self) -> View {
+        View(self.0.clone())
+    }
+}
+
+/// A handle onto a stored result that reads as a slice.
+struct View(Arc<Vec<usize>>);
+
+impl Deref for View {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.0
+    }
+}
+
+impl Database for Db {
+    type Query = Key;
+
+    fn dispatch<D>(d: D, q: Key) -> D::Result
+    where
+        D: Dispatch<Self>,
+    {
+        match q {
+            Key::Numbers(numbers) => d.dispatch(numbers),
+            Key::Evens(evens) => d.dispatch(evens),
+        }
+    }
+}
+
+impl Query<Db> for Numbers {
+    type Result = Shared;
+
+    fn storage(db: &Db) -> &Storage<Self, Shared> {
+        &db.numbers
+    }
+
+    fn rule(_: &Context<Db>, query: &Self) -> Shared {
+        Shared(Arc::new((1..=query.0).collect()))
+    }
+}
+
+impl Query<Db> for Evens {
+    type Result = Shared;
+
+    fn storage(db: &Db) -> &Storage<Self, Shared> {
+        &db.evens
+    }
+
+    fn rule(context: &Context<Db>, query: &Self) -> Shared {
+        let numbers = context.fetch_materialized(&Numbers(query.0));
+        Shared(Arc::new(
+            numbers.iter().copied().filter(|n| n % 2 == 0).collect(),
+        ))
+    }
+}
+
+#[test]
+fn materialized_results_are_views_of_the_stored_vec() {
+    let context = Context::new(Db::default());
+    let evens = context.fetch_materialized(&Evens(6));
+    assert_eq!(&*evens, [2, 4, 6]);
+    let numbers = context.fetch_materialized(&Numbers(6));
+    assert_eq!(numbers.len(), 6);
+    let stored = context.fetch(&Numbers(6));
+    assert!(Arc::ptr_eq(&numbers.0, &stored.0));
+}
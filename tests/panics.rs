@@ -0,0 +1,104 @@
+mod common;
+
+use common::{Node, context};
+
+#[test]
+fn fetch_or_default_returns_default_on_cycle() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)) + 1);
+    db.set_rule(Node(2), |c| c.fetch_or_default(&Node(1)) + 10);
+    assert_eq!(context.fetch(&Node(1)), 11);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn result_degraded_by_a_caught_panic_is_not_cached() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)) + 1);
+    db.set_rule(Node(2), |c| c.fetch_or_default(&Node(1)) + 10);
+    context.fetch(&Node(1));
+    // Node(1) is stored now, so Node(2) recovers the real value.
+    assert_eq!(context.fetch(&Node(2)), 21);
+    assert_eq!(db.runs(Node(2)), 2);
+}
+
+#[test]
+fn fetch_catch_passes_foreign_payloads_on() {
+    let context = context();
+    context
+        .database()
+        .set_rule(Node(1), |_| std::panic::panic_any(42u32));
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _ = context.fetch_catch(&Node(1));
+    }))
+    .unwrap_err();
+    assert_eq!(payload.downcast_ref::<u32>(), Some(&42));
+}
+
+#[test]
+fn panic_message_names_the_chain_of_queries() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)) + 1);
+    db.set_rule(Node(2), |_| panic!("rule failed"));
+    let panic = context.fetch_catch(&Node(1)).unwrap_err();
+    // With the `backtrace` feature the backtraces follow the chain.
+    assert!(
+        panic
+            .message
+            .starts_with("rule failed\nwhile computing Node(Node(2)) <- Node(Node(1))")
+    );
+}
+
+#[test]
+fn dependencies_are_recorded_after_a_caught_panic() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(5), |c| c.fetch(&Node(6)));
+    db.set_rule(Node(6), |_| panic!("rule failed"));
+    db.set_rule(Node(1), |c| c.fetch(&Node(2)) + c.fetch(&Node(3)));
+    let caught = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        context.fetch(&Node(5));
+    }));
+    assert!(caught.is_err());
+    assert_eq!(context.fetch(&Node(1)), 5);
+    assert_eq!(
+        context.dependencies_of(&Node(1)),
+        Some(vec![Node(2).into(), Node(3).into()])
+    );
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn dependencies_are_recorded_around_a_panic_caught_in_a_rule() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(5), |c| c.fetch(&Node(6)));
+    db.set_rule(Node(6), |_| panic!("rule failed"));
+    db.set_rule(Node(1), |c| {
+        let _ = c.fetch_catch(&Node(5));
+        c.fetch(&Node(2))
+    });
+    assert_eq!(
+        context.fetch_with_deps(&Node(1)),
+        (2, vec![Node(5).into(), Node(2).into()])
+    );
+    assert_eq!(context.dependencies_of(&Node(5)), None);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn fetch_catch_returns_the_panic_and_leaves_the_database_usable() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |_| panic!("rule failed"));
+    let panic = context.fetch_catch(&Node(1)).unwrap_err();
+    assert!(panic.message.starts_with("rule failed"));
+    assert_eq!(context.owner_of(&Node(1)), None);
+    assert_eq!(context.check_invariants(), Ok(()));
+    assert_eq!(context.fetch_catch(&Node(2)).unwrap(), 2);
+    db.set_rule(Node(1), |_| 1);
+    assert_eq!(context.fetch(&Node(1)), 1);
+}
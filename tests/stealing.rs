@@ -0,0 +1,237 @@
+mod common;
+
+use common::{Key, Node, context, sleep, wait_until};
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+#[test]
+fn hinting_a_query_twice_offers_it_once() {
+    let context = context();
+    context.hint_dependencies([Key::Node(Node(1)), Key::Node(Node(1))]);
+    context.quiesce();
+    assert_eq!(context.database().runs(Node(1)), 1);
+    assert_eq!(context.stats().successful_steals, 1);
+    assert_eq!(context.stats().wasted_steals, 0);
+}
+
+#[test]
+fn a_taken_query_can_be_offered_again() {
+    let context = context();
+    context.hint_dependencies([Key::Node(Node(1))]);
+    context.quiesce();
+    context.invalidate_type::<Node>();
+    context.hint_dependencies([Key::Node(Node(1))]);
+    context.quiesce();
+    assert_eq!(context.database().runs(Node(1)), 2);
+}
+
+#[test]
+fn a_panic_in_stolen_work_surfaces_where_the_query_is_fetched() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        c.hint_dependencies([Key::Node(Node(3))]);
+        wait_until(|| c.database().runs(Node(3)) > 0);
+        1
+    });
+    db.set_rule(Node(3), |_| panic!("stolen rule failed"));
+    std::thread::scope(|s| {
+        let threads = [
+            s.spawn(|| context.fetch(&Node(1))),
+            s.spawn(|| context.fetch(&Node(1))),
+        ];
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 1);
+        }
+    });
+    assert_eq!(context.check_invariants(), Ok(()));
+    let fetched =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| context.fetch(&Node(3))));
+    assert!(fetched.is_err());
+    assert_eq!(db.runs(Node(3)), 2);
+}
+
+#[test]
+fn a_panicking_task_does_not_unwind_quiesce() {
+    let context = context();
+    context.spawn(|_| panic!("task failed"));
+    context.spawn(|c| {
+        c.fetch(&Node(1));
+    });
+    context.quiesce();
+    assert_eq!(context.database().runs(Node(1)), 1);
+}
+
+#[test]
+fn a_waiter_woken_for_other_work_keeps_waiting() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        wait_until(|| c.database().runs(Node(2)) >= 10);
+        1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        let waiting = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.stats().empty_steals > 0);
+        for _ in 0..10 {
+            context.spawn(|c| {
+                c.compute_uncached(&Node(2));
+            });
+        }
+        assert_eq!(computing.join().unwrap(), 1);
+        assert_eq!(waiting.join().unwrap(), 1);
+    });
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn seeding_a_query_from_two_threads_runs_it_once() {
+    let context = context();
+    context.database().set_rule(Node(1), |_| {
+        sleep(10);
+        1
+    });
+    std::thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| context.compute_all([Node(1), Node(2)]));
+        }
+    });
+    assert_eq!(context.database().runs(Node(1)), 1);
+    assert_eq!(context.database().runs(Node(2)), 1);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn quiesce_waits_for_a_stolen_prefetch() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        c.hint_dependencies([Key::Node(Node(2))]);
+        wait_until(|| c.database().runs(Node(2)) > 0);
+        1
+    });
+    db.set_rule(Node(2), |_| {
+        sleep(50);
+        2
+    });
+    std::thread::scope(|s| {
+        s.spawn(|| context.fetch(&Node(1)));
+        s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| db.runs(Node(2)) > 0);
+        context.quiesce();
+        assert_eq!(context.dependencies_of(&Node(2)), Some(vec![]));
+    });
+}
+
+#[test]
+fn quiesce_waits_for_a_stolen_task() {
+    let context = context();
+    let started = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    context.database().set_rule(Node(1), |c| {
+        wait_until(|| c.stats().successful_steals > 0);
+        1
+    });
+    std::thread::scope(|s| {
+        s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.stats().empty_steals > 0);
+        let task = (started.clone(), finished.clone());
+        context.spawn(move |_| {
+            let (started, finished) = task;
+            started.store(true, Ordering::Relaxed);
+            sleep(50);
+            finished.store(true, Ordering::Relaxed);
+        });
+        wait_until(|| started.load(Ordering::Relaxed));
+        context.quiesce();
+        assert!(finished.load(Ordering::Relaxed));
+    });
+}
+
+#[test]
+fn a_spawned_task_runs_on_a_waiting_thread() {
+    let context = context();
+    let db = context.database();
+    let ran_on = Arc::new(Mutex::new(None));
+    db.set_rule(Node(1), |c| {
+        wait_until(|| c.dependencies_of(&Node(5)).is_some());
+        c.fetch(&Node(5)) + 1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        let waiting = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.stats().empty_steals > 0);
+        let recorded = ran_on.clone();
+        context.spawn(move |c| {
+            *recorded.lock().unwrap() = Some(std::thread::current().id());
+            c.fetch(&Node(5));
+        });
+        let waiting_id = waiting.thread().id();
+        assert_eq!(computing.join().unwrap(), 6);
+        assert_eq!(waiting.join().unwrap(), 6);
+        assert_eq!(*ran_on.lock().unwrap(), Some(waiting_id));
+    });
+    assert_eq!(db.runs(Node(5)), 1);
+}
+
+#[test]
+fn fetching_what_a_waiter_stole_is_not_a_cycle() {
+    let context = context();
+    let db = context.database();
+    db.set_rule(Node(1), |c| {
+        c.hint_dependencies([Key::Node(Node(2))]);
+        wait_until(|| c.database().runs(Node(2)) > 0);
+        c.fetch(&Node(2)) + 1
+    });
+    db.set_rule(Node(2), |_| {
+        sleep(50);
+        2
+    });
+    std::thread::scope(|s| {
+        let threads = [
+            s.spawn(|| context.fetch(&Node(1))),
+            s.spawn(|| context.fetch(&Node(1))),
+        ];
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 3);
+        }
+    });
+    assert_eq!(db.runs(Node(2)), 1);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn hinted_dependencies_are_computed_in_parallel() {
+    let context = context();
+    let db = context.database();
+    for i in 2..=5 {
+        db.set_rule(Node(i), move |_| {
+            sleep(50);
+            i.into()
+        });
+    }
+    db.set_rule(Node(1), |c| {
+        c.hint_dependencies((2..=5).map(|i| Key::Node(Node(i))));
+        (2..=5).map(|i| c.fetch(&Node(i))).sum()
+    });
+    let started = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(context.fetch(&Node(1)), 14));
+        }
+    });
+    assert!(started.elapsed() < Duration::from_millis(150));
+    for i in 2..=5 {
+        assert_eq!(db.runs(Node(i)), 1);
+    }
+}
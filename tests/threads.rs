@@ -0,0 +1,124 @@
+mod common;
+
+use common::{Key, Node, context, database, wait_until};
+use rockrs::{BusySpin, ContextBuilder, Park, WaitStrategy, YieldThenPark};
+use std::sync::Arc;
+
+#[test]
+fn fetches_on_scoped_threads_are_dependencies_of_the_rule() {
+    let context = context();
+    context.database().set_rule(Node(1), |c| {
+        c.scope(|scope| {
+            let left = scope.spawn(|c| c.fetch(&Node(2)));
+            let right = scope.spawn(|c| c.fetch(&Node(3)));
+            left.join().unwrap() + right.join().unwrap()
+        })
+    });
+    assert_eq!(context.fetch(&Node(1)), 5);
+    let mut dependencies = context.dependencies_of(&Node(1)).unwrap();
+    dependencies.sort();
+    assert_eq!(dependencies, [Key::Node(Node(2)), Key::Node(Node(3))]);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+fn waiters_are_woken_with(wait_strategy: impl WaitStrategy + 'static) {
+    let context = ContextBuilder::new(database())
+        .wait_strategy(wait_strategy)
+        .build();
+    for i in 0..100 {
+        std::thread::scope(|s| {
+            let computing = s.spawn(|| context.fetch(&Node(i)));
+            let waiting = s.spawn(|| context.fetch(&Node(i)));
+            assert_eq!(computing.join().unwrap(), i.into());
+            assert_eq!(waiting.join().unwrap(), i.into());
+        });
+    }
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn waiters_are_woken_when_parked() {
+    waiters_are_woken_with(Park);
+}
+
+#[test]
+fn waiters_are_woken_when_spinning() {
+    waiters_are_woken_with(BusySpin);
+}
+
+#[test]
+fn waiters_are_woken_after_yielding() {
+    waiters_are_woken_with(YieldThenPark { yields: 10 });
+}
+
+#[test]
+fn a_waiter_is_woken_when_the_computing_thread_finishes() {
+    let context = context();
+    context.database().set_rule(Node(1), |c| {
+        wait_until(|| c.stats().empty_steals > 0);
+        1
+    });
+    std::thread::scope(|s| {
+        let computing = s.spawn(|| context.fetch(&Node(1)));
+        wait_until(|| context.owner_of(&Node(1)).is_some());
+        assert_eq!(context.fetch(&Node(1)), 1);
+        assert_eq!(computing.join().unwrap(), 1);
+    });
+    assert_eq!(context.database().runs(Node(1)), 1);
+}
+
+#[test]
+fn an_arc_context_is_shared_by_spawned_threads() {
+    let context = Arc::new(context());
+    for i in 2..=20 {
+        context
+            .database()
+            .set_rule(Node(i), move |c| c.fetch(&Node(i - 1)) + 1);
+    }
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let context = context.clone();
+            std::thread::spawn(move || context.fetch(&Node(20)))
+        })
+        .collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 20);
+    }
+    assert_eq!(context.database().runs(Node(20)), 1);
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn threads_leave_no_state_behind() {
+    let context = context();
+    context.database().set_rule(Node(1), |c| {
+        (0..10)
+            .map(|i| c.scope(|scope| scope.spawn(move |c| c.fetch(&Node(i + 2))).join().unwrap()))
+            .sum()
+    });
+    context.fetch(&Node(1));
+    assert_eq!(context.check_invariants(), Ok(()));
+}
+
+#[test]
+fn a_weak_context_stops_upgrading_once_the_context_is_dropped() {
+    let context = Arc::new(context());
+    let weak = context.downgrade();
+    let (ready, wait_for_drop) = std::sync::mpsc::channel();
+    let background = std::thread::spawn(move || {
+        let mut fetched = 0;
+        while let Some(context) = weak.upgrade() {
+            context.fetch(&Node(fetched));
+            fetched += 1;
+            if fetched == 10 {
+                ready.send(()).unwrap();
+            }
+            drop(context);
+            std::thread::yield_now();
+        }
+        fetched
+    });
+    wait_for_drop.recv().unwrap();
+    drop(context);
+    assert!(background.join().unwrap() >= 10);
+}